@@ -1,32 +1,61 @@
+use axum::extract::{FromRef, Query};
+use axum::response::sse::{Event, KeepAlive, Sse};
 use axum::routing::{get, post, put};
 use axum::{Json, Router, extract::State};
 use clap::Parser;
-use notify::{RecommendedWatcher, Watcher};
+use crossbeam_queue::ArrayQueue;
+use futures::stream::Stream;
+use notify::{PollWatcher, RecommendedWatcher, Watcher};
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
-use std::path::PathBuf;
-use std::sync::{Arc, RwLock};
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::io::AsyncReadExt;
+use tokio::sync::{broadcast, Semaphore};
 use tokio;
 
 type AppState = Arc<RwLock<ServerState>>;
 
+/// a hashing job enqueued for `path`, and the seq of the `New` SSE event it
+/// was enqueued alongside. not persisted across restarts. lets a completing
+/// `hash_and_reconcile` tell whether it's still reconciling the latest
+/// request for `path` or was superseded by a newer one (e.g. the path was
+/// removed and recreated with different content while the older hash was
+/// still in flight), and which already-published event to retract if the
+/// hash turns out to be a no-op revert - see `hash_and_reconcile`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct PendingHash {
+    generation: u64,
+    publish_seq: u64,
+}
+
 /// keeps track of the changed paths
 ///
-/// this is simple - it does not understand if a folder is renamed then all the
-/// contained files also count as renamed
+/// when a directory is renamed, `apply_rename_cascade` reattributes every
+/// tracked path underneath it rather than leaving them pointing at the old
+/// location
 ///
-/// it also doesn't differentiate between folder and files paths. possibly
-/// problematic, since a notify rename event doesn't know if it's a folder or
-/// file being renamed
+/// it still doesn't differentiate between folder and file paths otherwise -
+/// possibly problematic, since a notify rename event doesn't know if it's a
+/// folder or file being renamed (this is why the directory-rename case has
+/// to be probed for rather than read directly off the event)
 ///
 /// however it does know if a file is created then removed, it's like it was
 /// never created in the first place
 #[derive(Debug, Default)]
 struct FileSystemChanges {
-    // hashset in case of large size O(1)
-    removed: HashSet<PathBuf>,
+    // sorted so a renamed directory's descendants sit in one contiguous
+    // range and can be found and rewritten without a full scan
+    removed: BTreeSet<PathBuf>,
     // contains both creation and modification
-    new: HashSet<PathBuf>,
+    new: BTreeSet<PathBuf>,
+    // last-known content hash for every path the hashing pool has confirmed.
+    // used to suppress "new" entries whose bytes round-tripped back to what
+    // they were before (e.g. editors that rewrite-in-place)
+    content_hashes: HashMap<PathBuf, [u8; 32]>,
+    // see `PendingHash`
+    pending_hashes: HashMap<PathBuf, PendingHash>,
 }
 
 #[derive(Debug)]
@@ -39,7 +68,7 @@ enum ServerState {
     /// needs full rescan due to erroneous state
     ///
     /// e.g. the server couldn't keep up with the rate of changes produced
-    /// 
+    ///
     /// has priority over TooManyChanges (can transition from it to this)
     ChangesErroneousDropped,
 }
@@ -50,6 +79,385 @@ impl Default for ServerState {
     }
 }
 
+/// on-disk form of `ServerState`, written by `write_snapshot` and read back
+/// by `load_snapshot`. kept separate from `ServerState` so the wire format
+/// doesn't have to move in lockstep with in-memory representation details
+#[derive(Debug, Serialize, Deserialize)]
+enum SnapshotState {
+    Ok {
+        new: BTreeSet<PathBuf>,
+        removed: BTreeSet<PathBuf>,
+        content_hashes: HashMap<PathBuf, [u8; 32]>,
+    },
+    TooManyChanges,
+    ChangesErroneousDropped,
+}
+
+impl From<&ServerState> for SnapshotState {
+    fn from(state: &ServerState) -> Self {
+        match state {
+            ServerState::Ok(changes) => SnapshotState::Ok {
+                new: changes.new.clone(),
+                removed: changes.removed.clone(),
+                content_hashes: changes.content_hashes.clone(),
+            },
+            ServerState::TooManyChanges => SnapshotState::TooManyChanges,
+            ServerState::ChangesErroneousDropped => SnapshotState::ChangesErroneousDropped,
+        }
+    }
+}
+
+impl From<SnapshotState> for ServerState {
+    fn from(snapshot: SnapshotState) -> Self {
+        match snapshot {
+            SnapshotState::Ok { new, removed, content_hashes } => {
+                ServerState::Ok(FileSystemChanges {
+                    new,
+                    removed,
+                    content_hashes,
+                    pending_hashes: HashMap::new(),
+                })
+            }
+            SnapshotState::TooManyChanges => ServerState::TooManyChanges,
+            SnapshotState::ChangesErroneousDropped => ServerState::ChangesErroneousDropped,
+        }
+    }
+}
+
+/// serializes `snapshot`, appends a trailing crc32 checksum, and writes it
+/// atomically: body is written to a temp file next to `path`, fsynced, then
+/// renamed over `path`. a crash mid-write can only ever leave the old
+/// snapshot or the temp file behind - never a half-written file at `path`
+/// that later passes the checksum
+fn write_snapshot(path: &std::path::Path, snapshot: &SnapshotState) -> std::io::Result<()> {
+    let mut bytes = bincode::serialize(snapshot)
+        .map_err(std::io::Error::other)?;
+    let checksum = crc32fast::hash(&bytes);
+    bytes.extend_from_slice(&checksum.to_le_bytes());
+
+    let tmp_path = path.with_extension("tmp");
+    {
+        use std::io::Write;
+        let mut file = std::fs::File::create(&tmp_path)?;
+        file.write_all(&bytes)?;
+        file.sync_all()?;
+    }
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// loads and verifies a snapshot written by `write_snapshot`. returns `None`
+/// on any failure - missing file, truncated/corrupt body, checksum
+/// mismatch, or a structurally invalid snapshot - so the caller can fall
+/// back to a safe default instead of trusting partial state
+fn load_snapshot(path: &std::path::Path) -> Option<ServerState> {
+    let bytes = std::fs::read(path).ok()?;
+    if bytes.len() < 4 {
+        return None;
+    }
+    let (body, checksum_bytes) = bytes.split_at(bytes.len() - 4);
+    let expected = u32::from_le_bytes(checksum_bytes.try_into().ok()?);
+    if crc32fast::hash(body) != expected {
+        return None;
+    }
+    let snapshot: SnapshotState = bincode::deserialize(body).ok()?;
+    Some(snapshot.into())
+}
+
+/// reusable read buffers for the hashing pool, so hashing a burst of files
+/// doesn't allocate a fresh `Vec` per file
+struct BufferPool {
+    buffers: ArrayQueue<Vec<u8>>,
+}
+
+impl BufferPool {
+    fn new(capacity: usize) -> Self {
+        Self {
+            buffers: ArrayQueue::new(capacity),
+        }
+    }
+
+    fn acquire(&self) -> Vec<u8> {
+        self.buffers.pop().unwrap_or_default()
+    }
+
+    fn release(&self, mut buf: Vec<u8>) {
+        buf.clear();
+        // best effort - if the pool is full just let the buffer drop
+        let _ = self.buffers.push(buf);
+    }
+}
+
+/// bounds how many files can be read and hashed concurrently, so a burst of
+/// thousands of fs events can't open thousands of file descriptors at once
+struct HashingPool {
+    semaphore: Semaphore,
+    buffers: BufferPool,
+    // monotonically increasing, handed out whenever a path is enqueued for
+    // hashing - see `PendingHash`
+    next_generation: AtomicU64,
+}
+
+impl HashingPool {
+    fn new(max_concurrency: usize) -> Self {
+        Self {
+            semaphore: Semaphore::new(max_concurrency),
+            buffers: BufferPool::new(max_concurrency),
+            next_generation: AtomicU64::new(0),
+        }
+    }
+}
+
+/// reads and hashes `path`, reconciling the result against `content_hashes`.
+///
+/// if the freshly computed hash matches the last known hash, the path is
+/// removed from `new` instead of being reported as a change, and a
+/// `Reconciled` event retracting the `New` event published when this job
+/// was enqueued is published in its place - otherwise a `/subscribe`
+/// consumer would report every create/modify as a real change even when
+/// it's actually a no-op revert, out of step with what `/drain_new` would
+/// return for the same window. if the file disappeared before hashing
+/// completed, the result is dropped silently - the remove event (if any)
+/// already updated the change sets. any other hashing error falls back to
+/// leaving the path reported as changed.
+///
+/// `generation` is the value `pending_hashes[path].generation` held when
+/// this job was enqueued. if `path` has since been enqueued again with a
+/// newer generation (e.g. it was removed and recreated with different
+/// content while this read was still in flight), that means this result is
+/// for a superseded request and is discarded without touching
+/// `content_hashes` - otherwise a slow, stale hash could read the *current*
+/// (newer) bytes, cache them, and let the newer job mistake its own fresh
+/// content for "unchanged", silently swallowing a genuine change
+async fn hash_and_reconcile(
+    path: PathBuf,
+    generation: u64,
+    state: AppState,
+    hashing: Arc<HashingPool>,
+    event_bus: Arc<EventBus>,
+) {
+    let _permit = match hashing.semaphore.acquire().await {
+        Ok(permit) => permit,
+        Err(_) => return, // semaphore closed, server shutting down
+    };
+
+    let mut buf = hashing.buffers.acquire();
+    let read_result = match tokio::fs::File::open(&path).await {
+        Ok(mut file) => file.read_to_end(&mut buf).await.map(|_| ()),
+        Err(e) => Err(e),
+    };
+
+    let hash_result = match read_result {
+        Ok(()) => Some(Ok(*blake3::hash(&buf).as_bytes())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => Some(Err(e)),
+    };
+
+    hashing.buffers.release(buf);
+
+    let mut state_lock = state.write().unwrap();
+    let ok_state = match &mut *state_lock {
+        ServerState::Ok(v) => v,
+        // state moved on (too many changes / erroneous) before hashing
+        // finished - a full rescan is coming anyway
+        _ => return,
+    };
+
+    let publish_seq = match ok_state.pending_hashes.get(&path) {
+        Some(pending) if pending.generation == generation => pending.publish_seq,
+        // superseded by a newer request for this path - see doc comment above
+        _ => return,
+    };
+
+    match hash_result {
+        None => {}
+        Some(Ok(digest)) => {
+            if ok_state.content_hashes.get(&path) == Some(&digest) {
+                // bytes are identical to the last known hash - not a real
+                // change. retract the `New` event published when this was
+                // enqueued so `/subscribe` matches `/drain_new`
+                ok_state.new.remove(&path);
+                event_bus.publish_change(ChangeEventKind::Reconciled { cancels: publish_seq }, path);
+            } else {
+                ok_state.content_hashes.insert(path, digest);
+            }
+        }
+        Some(Err(e)) => {
+            log::warn!("failed to hash {:?}, reporting as changed: {:?}", path, e);
+        }
+    }
+}
+
+/// which side of `FileSystemChanges` a `ChangeEvent` landed in, or that a
+/// previously published `New` turned out to be a no-op revert
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ChangeEventKind {
+    New,
+    Removed,
+    /// the content-hash pool confirmed the `New` event with seq `cancels`
+    /// round-tripped back to its last known content - retract it
+    Reconciled { cancels: u64 },
+}
+
+/// a single change, in the order it was observed. pushed over the
+/// `/subscribe` SSE stream so scanners don't have to poll `/drain_new` /
+/// `/drain_removed`
+#[derive(Debug, Clone)]
+struct ChangeEvent {
+    seq: u64,
+    kind: ChangeEventKind,
+    path: PathBuf,
+}
+
+/// what gets fanned out to `/subscribe` subscribers. terminal state
+/// transitions are published the same way as ordinary changes so a
+/// connected subscriber is told to rescan without having to also poll
+/// `/stats`
+#[derive(Debug, Clone)]
+enum BroadcastMessage {
+    Change(Arc<ChangeEvent>),
+    TooManyChanges,
+    ChangesErroneousDropped,
+}
+
+/// assigns each change a monotonically increasing sequence number and fans
+/// it out to `/subscribe` subscribers over a bounded broadcast channel.
+/// `recent` retains the same number of events as the broadcast channel's
+/// capacity so a reconnecting subscriber can pass `?from=<seq>` and be
+/// backfilled instead of missing everything sent while it was away
+struct EventBus {
+    sender: broadcast::Sender<BroadcastMessage>,
+    recent: Mutex<VecDeque<Arc<ChangeEvent>>>,
+    next_seq: AtomicU64,
+    capacity: usize,
+}
+
+impl EventBus {
+    fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        Self {
+            sender,
+            recent: Mutex::new(VecDeque::with_capacity(capacity)),
+            next_seq: AtomicU64::new(0),
+            capacity,
+        }
+    }
+
+    /// records and publishes a change, returning its assigned sequence
+    /// number
+    fn publish_change(&self, kind: ChangeEventKind, path: PathBuf) -> u64 {
+        let seq = self.next_seq.fetch_add(1, Ordering::SeqCst);
+        let event = Arc::new(ChangeEvent { seq, kind, path });
+
+        // held across the send too, so `subscribe` can never observe a
+        // `recent` snapshot that's missing an event already delivered to
+        // live receivers (or vice versa)
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() == self.capacity {
+            recent.pop_front();
+        }
+        recent.push_back(event.clone());
+        // no active subscribers is not an error - the stream simply has no
+        // listeners right now
+        let _ = self.sender.send(BroadcastMessage::Change(event));
+        seq
+    }
+
+    /// publishes a terminal state transition (no sequence number - these
+    /// aren't entries in `new`/`removed`)
+    fn publish_terminal(&self, msg: BroadcastMessage) {
+        let recent = self.recent.lock().unwrap();
+        let _ = self.sender.send(msg);
+        drop(recent);
+    }
+
+    /// returns the retained events after `from` (or all retained events if
+    /// `from` is `None`), and a receiver subscribed at the same instant the
+    /// snapshot was taken, so no event can fall in the gap between the two
+    fn subscribe(&self, from: Option<u64>) -> (Vec<Arc<ChangeEvent>>, broadcast::Receiver<BroadcastMessage>) {
+        let recent = self.recent.lock().unwrap();
+        let receiver = self.sender.subscribe();
+        let backlog = recent
+            .iter()
+            .filter(|event| from.is_none_or(|from| event.seq > from))
+            .cloned()
+            .collect();
+        (backlog, receiver)
+    }
+}
+
+/// returns every path in `set` that is a strict descendant of `from`.
+///
+/// `set` is sorted, and path ordering groups a directory immediately before
+/// all of its descendants (a shorter path sorts before a longer path that
+/// shares its prefix), so the matches form one contiguous range starting
+/// just after `from` - no full scan needed
+fn rename_cascade_paths(set: &BTreeSet<PathBuf>, from: &Path) -> Vec<PathBuf> {
+    set.range((std::ops::Bound::Excluded(from.to_path_buf()), std::ops::Bound::Unbounded))
+        .take_while(|path| path.starts_with(from))
+        .cloned()
+        .collect()
+}
+
+/// reattributes every tracked descendant of a renamed directory from `from`
+/// to `to`, preserving which set (`new` or `removed`) each descendant was
+/// in and the existing "created-then-removed cancels out" invariant.
+///
+/// the cascade only ever remaps paths that were already counted against
+/// `change_limit` before the rename started - each iteration removes
+/// exactly one path from a set and inserts at most one (fewer on a
+/// destination collision), so `new.len() + removed.len()` can only stay
+/// flat or shrink here. the caller's own change_limit check (on the
+/// from/to pair itself) is what actually bounds growth
+fn apply_rename_cascade(ok_state: &mut FileSystemChanges, from: &Path, to: &Path, event_bus: &EventBus) {
+    let new_descendants = rename_cascade_paths(&ok_state.new, from);
+    let removed_descendants = rename_cascade_paths(&ok_state.removed, from);
+
+    for old_path in new_descendants {
+        let rel = old_path.strip_prefix(from).expect("matched by prefix scan");
+        let new_path = to.join(rel);
+
+        ok_state.new.remove(&old_path);
+        if let Some(hash) = ok_state.content_hashes.remove(&old_path) {
+            ok_state.content_hashes.insert(new_path.clone(), hash);
+        }
+        // any hash still in flight for `old_path` is now for a name nothing
+        // is tracked under - let it complete and be discarded as stale
+        // rather than trying to re-key it to `new_path`
+        ok_state.pending_hashes.remove(&old_path);
+        ok_state.removed.remove(&new_path);
+        ok_state.new.insert(new_path.clone());
+        event_bus.publish_change(ChangeEventKind::New, new_path);
+    }
+
+    for old_path in removed_descendants {
+        let rel = old_path.strip_prefix(from).expect("matched by prefix scan");
+        let new_path = to.join(rel);
+
+        ok_state.removed.remove(&old_path);
+        ok_state.content_hashes.remove(&old_path);
+        ok_state.pending_hashes.remove(&old_path);
+        ok_state.new.remove(&new_path);
+        ok_state.removed.insert(new_path.clone());
+        event_bus.publish_change(ChangeEventKind::Removed, new_path);
+    }
+}
+
+/// which notify backend watches `file_path`
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum WatcherBackend {
+    /// OS-level notifications (inotify/FSEvents/...). Lowest latency, but
+    /// doesn't fire on NFS, SMB, many container overlay mounts, or
+    /// bind-mounted volumes
+    Native,
+    /// compares directory snapshots on `--poll-interval`. Works anywhere,
+    /// at the cost of latency. `ModifyKind::Name(RenameMode::Both)` may
+    /// never be produced this way - a poll-detected rename can surface as
+    /// a plain create/remove pair instead, which the existing fallbacks
+    /// already cover
+    Poll,
+}
+
 #[derive(Parser, Debug)]
 #[command(name = "incremental-agent")]
 #[command(about = "Keeps track of changes since previous scan", long_about = None)]
@@ -73,6 +481,58 @@ struct Cli {
     /// Port for the HTTP server
     #[arg(long = "port", default_value_t = 8080)]
     port: u16,
+
+    /// Maximum number of files being hashed at once by the content-hash
+    /// verification pool. Must be at least 1
+    #[arg(long = "hash-concurrency", default_value_t = 64, value_parser = clap::value_parser!(u64).range(1..))]
+    hash_concurrency: u64,
+
+    /// Path to the checkpoint file used to persist tracked changes across
+    /// restarts
+    #[arg(long = "snapshot-path", default_value = "./incremental-agent.snapshot")]
+    snapshot_path: PathBuf,
+
+    /// How often (in seconds) to flush the change-set checkpoint to disk
+    #[arg(long = "snapshot-interval", default_value_t = 30)]
+    snapshot_interval_secs: u64,
+
+    /// Number of recent events retained for `/subscribe` fan-out and
+    /// `?from=` backfill. A subscriber that falls further behind than this
+    /// is told to rescan instead of being buffered indefinitely. Must be
+    /// at least 1
+    #[arg(long = "event-channel-capacity", default_value_t = 4096, value_parser = clap::value_parser!(u64).range(1..))]
+    event_channel_capacity: u64,
+
+    /// Which notify backend to use for watching `file_path`. `poll` works
+    /// on filesystems where OS-level notifications don't fire (NFS, SMB,
+    /// many container overlay or bind mounts)
+    #[arg(long = "watcher-backend", value_enum, default_value = "native")]
+    watcher_backend: WatcherBackend,
+
+    /// Poll interval in seconds, only used when `--watcher-backend poll` is set
+    #[arg(long = "poll-interval", default_value_t = 30)]
+    poll_interval: u64,
+}
+
+/// combined axum state: `State<AppState>` and `State<Arc<EventBus>>` are
+/// both derived from this via `FromRef`, so existing handlers that only
+/// need one of the two don't have to change
+#[derive(Clone)]
+struct AppContext {
+    state: AppState,
+    event_bus: Arc<EventBus>,
+}
+
+impl FromRef<AppContext> for AppState {
+    fn from_ref(ctx: &AppContext) -> AppState {
+        ctx.state.clone()
+    }
+}
+
+impl FromRef<AppContext> for Arc<EventBus> {
+    fn from_ref(ctx: &AppContext) -> Arc<EventBus> {
+        ctx.event_bus.clone()
+    }
 }
 
 async fn reset_handler(State(state): State<AppState>) {
@@ -123,9 +583,25 @@ struct ReturnVal {
     done: bool,
 }
 
+/// a path reported by `drain_new`, alongside its content hash if the
+/// hashing pool has confirmed one. the scanner can skip re-reading a path
+/// it already has bytes for under the same hash
+#[derive(Serialize)]
+struct NewChangeEntry {
+    path: PathBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hash: Option<[u8; 32]>,
+}
+
+#[derive(Serialize)]
+struct NewReturnVal {
+    paths: Vec<NewChangeEntry>,
+    done: bool,
+}
+
 #[derive(Serialize)]
 enum ServerNewChangesResponse {
-    New(ReturnVal),
+    New(NewReturnVal),
     TooManyChanges,
     ChangesErroneousDropped,
 }
@@ -156,15 +632,16 @@ async fn drain_new_handler(
         req.size = 1000;
     }
 
-    let mut drained = ReturnVal { paths: Vec::new(), done: false };
+    let mut drained = NewReturnVal { paths: Vec::new(), done: false };
 
     for _ in 0..req.size {
         // this is the fastest but not very efficient for hash set
         let elem = ok_state.new.iter().next().cloned();
         match elem {
             Some(elem) => {
-                drained.paths.push(elem.clone());
+                let hash = ok_state.content_hashes.get(&elem).copied();
                 ok_state.new.remove(&elem);
+                drained.paths.push(NewChangeEntry { path: elem, hash });
             },
             None => {
                 drained.done = true;
@@ -210,6 +687,99 @@ async fn drain_removed_handler(
     axum::Json(ServerRemoveChangesResponse::Removed(drained))
 }
 
+#[derive(Serialize)]
+struct ChangeEventPayload<'a> {
+    seq: u64,
+    path: &'a PathBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cancels: Option<u64>,
+}
+
+fn change_event_to_sse(event: &ChangeEvent) -> Event {
+    let (name, cancels) = match event.kind {
+        ChangeEventKind::New => ("new", None),
+        ChangeEventKind::Removed => ("removed", None),
+        ChangeEventKind::Reconciled { cancels } => ("reconciled", Some(cancels)),
+    };
+    let payload = ChangeEventPayload { seq: event.seq, path: &event.path, cancels };
+    let data = serde_json::to_string(&payload).unwrap_or_default();
+    Event::default().event(name).id(event.seq.to_string()).data(data)
+}
+
+#[derive(Deserialize)]
+struct SubscribeQuery {
+    from: Option<u64>,
+}
+
+/// streams changes as they occur instead of requiring the scanner to poll
+/// `/drain_new` / `/drain_removed`. pass `?from=<seq>` (the last sequence
+/// number seen) to resume after a disconnect - any retained events after
+/// that point are replayed before live events start flowing. if events after
+/// `from` were already evicted, a single `rescan` event is sent instead
+async fn subscribe_handler(
+    State(state): State<AppState>,
+    State(event_bus): State<Arc<EventBus>>,
+    Query(query): Query<SubscribeQuery>,
+) -> Sse<impl Stream<Item = Result<Event, std::convert::Infallible>>> {
+    let (backlog, mut receiver) = event_bus.subscribe(query.from);
+
+    // events between `from` and the oldest retained one were already evicted
+    // from `recent`, so this subscriber can't be caught up - same as a live lag
+    let backlog_gap = match (query.from, backlog.first()) {
+        (Some(from), Some(first)) => first.seq - 1 > from,
+        _ => false,
+    };
+
+    // a terminal transition is only broadcast once, to whoever was
+    // subscribed at that instant. a subscriber connecting (or reconnecting)
+    // after the transition already happened would otherwise wait on
+    // `receiver.recv()` forever - check the current state up front and
+    // report it immediately instead of entering the live loop
+    let already_terminal = match &*state.read().unwrap() {
+        ServerState::Ok(_) => None,
+        ServerState::TooManyChanges => Some(Event::default().event("too_many_changes").data("")),
+        ServerState::ChangesErroneousDropped => {
+            Some(Event::default().event("changes_erroneous_dropped").data(""))
+        }
+    };
+
+    let stream = async_stream::stream! {
+        if let Some(event) = already_terminal {
+            yield Ok(event);
+        } else if backlog_gap {
+            yield Ok(Event::default().event("rescan").data(""));
+        } else {
+            for event in backlog {
+                yield Ok(change_event_to_sse(&event));
+            }
+
+            loop {
+                match receiver.recv().await {
+                    Ok(BroadcastMessage::Change(event)) => yield Ok(change_event_to_sse(&event)),
+                    Ok(BroadcastMessage::TooManyChanges) => {
+                        yield Ok(Event::default().event("too_many_changes").data(""));
+                        break;
+                    }
+                    Ok(BroadcastMessage::ChangesErroneousDropped) => {
+                        yield Ok(Event::default().event("changes_erroneous_dropped").data(""));
+                        break;
+                    }
+                    Err(broadcast::error::RecvError::Lagged(_)) => {
+                        // fell behind the bounded channel's capacity - tell the
+                        // subscriber to fall back to a full rescan rather than
+                        // buffering an unbounded backlog to catch it up
+                        yield Ok(Event::default().event("rescan").data(""));
+                        break;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        }
+    };
+
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let cli = Cli::parse();
@@ -217,11 +787,68 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .init();
     log::debug!("{:?}", cli);
 
-    let server_state: AppState = Arc::new(RwLock::new(ServerState::default()));
-    let mut watcher = {
+    let initial_state = match load_snapshot(&cli.snapshot_path) {
+        Some(state) => {
+            log::info!("resumed tracked changes from snapshot at {:?}", cli.snapshot_path);
+            state
+        }
+        None if cli.snapshot_path.exists() => {
+            log::warn!(
+                "snapshot at {:?} failed verification, forcing a full rescan",
+                cli.snapshot_path
+            );
+            ServerState::ChangesErroneousDropped
+        }
+        None => ServerState::default(),
+    };
+
+    let server_state: AppState = Arc::new(RwLock::new(initial_state));
+    let hashing_pool = Arc::new(HashingPool::new(cli.hash_concurrency as usize));
+    let event_bus = Arc::new(EventBus::new(cli.event_channel_capacity as usize));
+    let rt_handle = tokio::runtime::Handle::current();
+
+    // a resumed snapshot's entries never went through `publish_change`, so
+    // without this a `/subscribe` client would silently diverge from
+    // `/drain_new` / `/drain_removed` right after a crash-recovery restart -
+    // it'd see an empty backlog despite there being restored changes.
+    // give them sequence numbers now so they're replayed like any other
+    // retained event (a no-op if the server started with a fresh/empty
+    // state instead of resuming one)
+    if let ServerState::Ok(changes) = &*server_state.read().unwrap() {
+        for path in &changes.new {
+            event_bus.publish_change(ChangeEventKind::New, path.clone());
+        }
+        for path in &changes.removed {
+            event_bus.publish_change(ChangeEventKind::Removed, path.clone());
+        }
+    }
+
+    {
         let server_state = server_state.clone();
-        RecommendedWatcher::new(
-            move |event: Result<notify::Event, notify::Error>| {
+        let snapshot_path = cli.snapshot_path.clone();
+        let flush_interval = std::time::Duration::from_secs(cli.snapshot_interval_secs);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(flush_interval);
+            loop {
+                interval.tick().await;
+                let snapshot = {
+                    let guard = server_state.read().unwrap();
+                    SnapshotState::from(&*guard)
+                };
+                let path = snapshot_path.clone();
+                match tokio::task::spawn_blocking(move || write_snapshot(&path, &snapshot)).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => log::error!("failed to write snapshot: {:?}", e),
+                    Err(e) => log::error!("snapshot task panicked: {:?}", e),
+                }
+            }
+        });
+    }
+    let handler = {
+        let server_state = server_state.clone();
+        let hashing_pool = hashing_pool.clone();
+        let event_bus = event_bus.clone();
+        move |event: Result<notify::Event, notify::Error>| {
                 let mut state_lock = server_state.write().unwrap();
 
                 let mut event = match event {
@@ -229,10 +856,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     Err(e) => {
                         log::error!("notify sent err: {:?}", e); // never?
                         *state_lock = ServerState::ChangesErroneousDropped;
+                        event_bus.publish_terminal(BroadcastMessage::ChangesErroneousDropped);
                         return;
                     }
                 };
-                
+
                 if event.need_rescan() {
                     // to test:
                     // sysctl -w fs.inotify.max_queued_events=2
@@ -242,6 +870,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     // sysctl -w fs.inotify.max_queued_events=16384
                     log::error!("notify sent rescan event"); // e.g. server can't keep up
                     *state_lock = ServerState::ChangesErroneousDropped;
+                    event_bus.publish_terminal(BroadcastMessage::ChangesErroneousDropped);
                     return;
                 }
 
@@ -250,18 +879,29 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     _ => return,
                 };
 
+                // paths that need their content hash (re)verified by the
+                // background hashing pool once this event has been applied
+                let mut to_hash: Vec<(PathBuf, u64)> = Vec::new();
+
                 match event.kind {
                     notify::EventKind::Create(_) => {
                         // from testing, this contains many files in one (notify lib accumulates them?)
                         event.paths.drain(..).for_each(|path| {
                             ok_state.removed.remove(&path);
-                            ok_state.new.insert(path);
+                            ok_state.new.insert(path.clone());
+                            let publish_seq = event_bus.publish_change(ChangeEventKind::New, path.clone());
+                            let generation = hashing_pool.next_generation.fetch_add(1, Ordering::SeqCst);
+                            ok_state.pending_hashes.insert(path.clone(), PendingHash { generation, publish_seq });
+                            to_hash.push((path, generation));
                         });
                     },
                     notify::EventKind::Remove(_) => {
                         event.paths.drain(..).for_each(|path| {
                             ok_state.new.remove(&path);
-                            ok_state.removed.insert(path);
+                            ok_state.removed.insert(path.clone());
+                            ok_state.content_hashes.remove(&path);
+                            ok_state.pending_hashes.remove(&path);
+                            event_bus.publish_change(ChangeEventKind::Removed, path);
                         });
                     },
                     notify::EventKind::Modify(modify_kind) => {
@@ -271,13 +911,20 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                                     notify::event::RenameMode::To => {
                                         event.paths.drain(..).for_each(|path| {
                                             ok_state.removed.remove(&path);
-                                            ok_state.new.insert(path);
+                                            ok_state.new.insert(path.clone());
+                                            let publish_seq = event_bus.publish_change(ChangeEventKind::New, path.clone());
+                                            let generation = hashing_pool.next_generation.fetch_add(1, Ordering::SeqCst);
+                                            ok_state.pending_hashes.insert(path.clone(), PendingHash { generation, publish_seq });
+                                            to_hash.push((path, generation));
                                         });
                                     },
                                     notify::event::RenameMode::From => {
                                         event.paths.drain(..).for_each(|path| {
                                             ok_state.new.remove(&path);
-                                            ok_state.removed.insert(path);
+                                            ok_state.removed.insert(path.clone());
+                                            ok_state.content_hashes.remove(&path);
+                                            ok_state.pending_hashes.remove(&path);
+                                            event_bus.publish_change(ChangeEventKind::Removed, path);
                                         });
                                     },
                                     notify::event::RenameMode::Both => {
@@ -286,10 +933,33 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                                             let to_path = event.paths.pop().unwrap();
                                             let from_path = event.paths.pop().unwrap();
 
+                                            // notify doesn't say whether a file or a
+                                            // directory moved. probe `to_path` on disk; a
+                                            // removed directory can't be probed that way,
+                                            // so also fall back to treating it as a
+                                            // directory rename if anything is already
+                                            // tracked underneath `from_path`
+                                            let is_dir_rename = std::fs::metadata(&to_path)
+                                                .map(|m| m.is_dir())
+                                                .unwrap_or(false)
+                                                || !rename_cascade_paths(&ok_state.new, &from_path).is_empty()
+                                                || !rename_cascade_paths(&ok_state.removed, &from_path).is_empty();
+
                                             ok_state.new.remove(&from_path);
-                                            ok_state.removed.insert(from_path);
+                                            ok_state.removed.insert(from_path.clone());
+                                            ok_state.content_hashes.remove(&from_path);
+                                            ok_state.pending_hashes.remove(&from_path);
+                                            event_bus.publish_change(ChangeEventKind::Removed, from_path.clone());
                                             ok_state.removed.remove(&to_path);
-                                            ok_state.new.insert(to_path);
+                                            ok_state.new.insert(to_path.clone());
+                                            let publish_seq = event_bus.publish_change(ChangeEventKind::New, to_path.clone());
+                                            let generation = hashing_pool.next_generation.fetch_add(1, Ordering::SeqCst);
+                                            ok_state.pending_hashes.insert(to_path.clone(), PendingHash { generation, publish_seq });
+                                            to_hash.push((to_path.clone(), generation));
+
+                                            if is_dir_rename {
+                                                apply_rename_cascade(ok_state, &from_path, &to_path, &event_bus);
+                                            }
                                         } else {
                                             debug_assert!(false, "notify rename both with != 2 paths");
                                         }
@@ -303,7 +973,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                                             // treat it as both
                                             log::warn!("notify unknown rename event for {:?}", path);
                                             ok_state.new.insert(path.clone());
-                                            ok_state.removed.insert(path);
+                                            ok_state.removed.insert(path.clone());
+                                            event_bus.publish_change(ChangeEventKind::New, path.clone());
+                                            event_bus.publish_change(ChangeEventKind::Removed, path);
                                         });
                                     }
                                 }
@@ -311,7 +983,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                             _ => {
                                 event.paths.drain(..).for_each(|path| {
                                     ok_state.removed.remove(&path);
-                                    ok_state.new.insert(path);
+                                    ok_state.new.insert(path.clone());
+                                    let publish_seq = event_bus.publish_change(ChangeEventKind::New, path.clone());
+                                    let generation = hashing_pool.next_generation.fetch_add(1, Ordering::SeqCst);
+                                    ok_state.pending_hashes.insert(path.clone(), PendingHash { generation, publish_seq });
+                                    to_hash.push((path, generation));
                                 });
                             }
                         }
@@ -323,22 +999,281 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 if total_changes > cli.change_limit {
                     log::warn!("change limit exceeded: {}", cli.change_limit);
                     *state_lock = ServerState::TooManyChanges;
+                    event_bus.publish_terminal(BroadcastMessage::TooManyChanges);
+                    return;
                 }
-            },
-            notify::Config::default(),
-        )?
+
+                drop(state_lock);
+                for (path, generation) in to_hash {
+                    rt_handle.spawn(hash_and_reconcile(
+                        path,
+                        generation,
+                        server_state.clone(),
+                        hashing_pool.clone(),
+                        event_bus.clone(),
+                    ));
+                }
+            }
+    };
+
+    // boxed as `dyn Watcher` so the backend can be picked at runtime - the
+    // `handler` closure above is shared between both, the two only differ
+    // in how they're constructed
+    let mut watcher: Box<dyn Watcher> = match cli.watcher_backend {
+        WatcherBackend::Native => Box::new(RecommendedWatcher::new(handler, notify::Config::default())?),
+        WatcherBackend::Poll => {
+            let config = notify::Config::default()
+                .with_poll_interval(std::time::Duration::from_secs(cli.poll_interval));
+            Box::new(PollWatcher::new(handler, config)?)
+        }
     };
 
     watcher.watch(&cli.file_path, notify::RecursiveMode::Recursive)?;
 
+    let app_context = AppContext { state: server_state, event_bus };
     let app = Router::new()
         .route("/reset", put(reset_handler))
         .route("/stats", get(stats_handler))
         .route("/drain_new", post(drain_new_handler))
-        .route("/drain_removed", post(drain_removed_handler)).with_state(server_state);
+        .route("/drain_removed", post(drain_removed_handler))
+        .route("/subscribe", get(subscribe_handler))
+        .with_state(app_context);
 
     let addr = format!("{}:{}", cli.bind_addr, cli.port);
     let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
     axum::serve(listener, app).await.unwrap();
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rename_cascade_paths_matches_only_strict_descendants() {
+        let mut set = BTreeSet::new();
+        for p in ["a/b", "a/b/c", "a/b/c/d", "a/bc", "a/b2", "ab/c"] {
+            set.insert(PathBuf::from(p));
+        }
+
+        let matches = rename_cascade_paths(&set, Path::new("a/b"));
+
+        assert_eq!(matches, vec![PathBuf::from("a/b/c"), PathBuf::from("a/b/c/d")]);
+    }
+
+    #[test]
+    fn rename_cascade_paths_empty_when_nothing_tracked_underneath() {
+        let mut set = BTreeSet::new();
+        set.insert(PathBuf::from("a/sibling"));
+
+        let matches = rename_cascade_paths(&set, Path::new("a/b"));
+
+        assert!(matches.is_empty());
+    }
+
+    #[test]
+    fn apply_rename_cascade_reattributes_descendants_and_hashes() {
+        let mut changes = FileSystemChanges::default();
+        changes.new.insert(PathBuf::from("src/a"));
+        changes.new.insert(PathBuf::from("src/a/file.txt"));
+        changes.removed.insert(PathBuf::from("src/a/old.txt"));
+        changes.content_hashes.insert(PathBuf::from("src/a/file.txt"), [7u8; 32]);
+
+        let event_bus = EventBus::new(16);
+        apply_rename_cascade(&mut changes, Path::new("src/a"), Path::new("src/b"), &event_bus);
+
+        assert!(changes.new.contains(&PathBuf::from("src/b/file.txt")));
+        assert!(!changes.new.contains(&PathBuf::from("src/a/file.txt")));
+        assert!(changes.removed.contains(&PathBuf::from("src/b/old.txt")));
+        assert!(!changes.removed.contains(&PathBuf::from("src/a/old.txt")));
+        assert_eq!(
+            changes.content_hashes.get(&PathBuf::from("src/b/file.txt")),
+            Some(&[7u8; 32]),
+        );
+        // "src/a" itself isn't a descendant of "src/a" - the rename of the
+        // directory entry itself is the caller's responsibility
+        assert!(changes.new.contains(&PathBuf::from("src/a")));
+    }
+
+    #[test]
+    fn apply_rename_cascade_destination_collision_cancels_out() {
+        // "src/a/file.txt" was newly created, but something already tracked
+        // as removed at the destination name - reattributing should let the
+        // existing "created-then-removed cancels out" invariant apply
+        let mut changes = FileSystemChanges::default();
+        changes.new.insert(PathBuf::from("src/a/file.txt"));
+        changes.removed.insert(PathBuf::from("src/b/file.txt"));
+
+        let event_bus = EventBus::new(16);
+        apply_rename_cascade(&mut changes, Path::new("src/a"), Path::new("src/b"), &event_bus);
+
+        assert!(changes.new.contains(&PathBuf::from("src/b/file.txt")));
+        assert!(!changes.removed.contains(&PathBuf::from("src/b/file.txt")));
+    }
+
+    fn temp_snapshot_path(tag: &str) -> PathBuf {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        let n = COUNTER.fetch_add(1, Ordering::SeqCst);
+        std::env::temp_dir().join(format!("incremental-poc-test-{}-{tag}-{n}.snapshot", std::process::id()))
+    }
+
+    #[test]
+    fn write_and_load_snapshot_round_trips() {
+        let path = temp_snapshot_path("roundtrip");
+        let mut new = BTreeSet::new();
+        new.insert(PathBuf::from("a/b.txt"));
+        let mut removed = BTreeSet::new();
+        removed.insert(PathBuf::from("c/d.txt"));
+        let mut content_hashes = HashMap::new();
+        content_hashes.insert(PathBuf::from("a/b.txt"), [3u8; 32]);
+        let snapshot = SnapshotState::Ok { new, removed, content_hashes };
+
+        write_snapshot(&path, &snapshot).unwrap();
+        let loaded = load_snapshot(&path).expect("freshly written snapshot should load");
+
+        match loaded {
+            ServerState::Ok(changes) => {
+                assert!(changes.new.contains(&PathBuf::from("a/b.txt")));
+                assert!(changes.removed.contains(&PathBuf::from("c/d.txt")));
+                assert_eq!(
+                    changes.content_hashes.get(&PathBuf::from("a/b.txt")),
+                    Some(&[3u8; 32]),
+                );
+            }
+            _ => panic!("expected ServerState::Ok"),
+        }
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn load_snapshot_rejects_corrupted_body() {
+        let path = temp_snapshot_path("corrupt");
+        write_snapshot(&path, &SnapshotState::TooManyChanges).unwrap();
+
+        // flip a byte in the body (not the trailing checksum) so it no
+        // longer matches the checksum written alongside it
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[0] ^= 0xff;
+        std::fs::write(&path, &bytes).unwrap();
+
+        assert!(load_snapshot(&path).is_none());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn load_snapshot_rejects_missing_file() {
+        let path = temp_snapshot_path("missing");
+        assert!(load_snapshot(&path).is_none());
+    }
+
+    fn temp_file_path(tag: &str) -> PathBuf {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        let n = COUNTER.fetch_add(1, Ordering::SeqCst);
+        std::env::temp_dir().join(format!("incremental-poc-test-{}-{tag}-{n}.txt", std::process::id()))
+    }
+
+    #[tokio::test]
+    async fn hash_and_reconcile_discards_stale_generation() {
+        let path = temp_file_path("stale-generation");
+        tokio::fs::write(&path, b"current content").await.unwrap();
+
+        let mut changes = FileSystemChanges::default();
+        changes.new.insert(path.clone());
+        // a newer request for this path was already enqueued (e.g. removed
+        // and recreated) after the job below was, so generation 1 is stale
+        changes.pending_hashes.insert(path.clone(), PendingHash { generation: 2, publish_seq: 0 });
+
+        let state: AppState = Arc::new(RwLock::new(ServerState::Ok(changes)));
+        let hashing = Arc::new(HashingPool::new(1));
+        let event_bus = Arc::new(EventBus::new(16));
+
+        hash_and_reconcile(path.clone(), 1, state.clone(), hashing, event_bus).await;
+
+        match &*state.read().unwrap() {
+            ServerState::Ok(changes) => {
+                assert!(changes.new.contains(&path), "stale result must not remove the path from `new`");
+                assert!(
+                    !changes.content_hashes.contains_key(&path),
+                    "stale result must not populate content_hashes"
+                );
+            }
+            _ => panic!("expected ServerState::Ok"),
+        }
+
+        tokio::fs::remove_file(&path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn hash_and_reconcile_applies_matching_generation() {
+        let path = temp_file_path("matching-generation");
+        tokio::fs::write(&path, b"hello").await.unwrap();
+
+        let mut changes = FileSystemChanges::default();
+        changes.new.insert(path.clone());
+        changes.pending_hashes.insert(path.clone(), PendingHash { generation: 1, publish_seq: 0 });
+
+        let state: AppState = Arc::new(RwLock::new(ServerState::Ok(changes)));
+        let hashing = Arc::new(HashingPool::new(1));
+        let event_bus = Arc::new(EventBus::new(16));
+
+        hash_and_reconcile(path.clone(), 1, state.clone(), hashing, event_bus).await;
+
+        let expected = *blake3::hash(b"hello").as_bytes();
+        match &*state.read().unwrap() {
+            ServerState::Ok(changes) => {
+                assert_eq!(changes.content_hashes.get(&path), Some(&expected));
+            }
+            _ => panic!("expected ServerState::Ok"),
+        }
+
+        tokio::fs::remove_file(&path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn hash_and_reconcile_retracts_new_event_on_no_op_revert() {
+        let path = temp_file_path("retract-on-revert");
+        tokio::fs::write(&path, b"original").await.unwrap();
+
+        let mut changes = FileSystemChanges::default();
+        changes.new.insert(path.clone());
+        changes.content_hashes.insert(path.clone(), *blake3::hash(b"original").as_bytes());
+
+        let event_bus = Arc::new(EventBus::new(16));
+        let publish_seq = event_bus.publish_change(ChangeEventKind::New, path.clone());
+        changes.pending_hashes.insert(path.clone(), PendingHash { generation: 0, publish_seq });
+
+        let state: AppState = Arc::new(RwLock::new(ServerState::Ok(changes)));
+        let hashing = Arc::new(HashingPool::new(1));
+        // subscribed right after the `New` was published, as a connected
+        // `/subscribe` client would be - should see the retraction live
+        let (_, mut receiver) = event_bus.subscribe(Some(publish_seq));
+
+        hash_and_reconcile(path.clone(), 0, state.clone(), hashing, event_bus.clone()).await;
+
+        match &*state.read().unwrap() {
+            ServerState::Ok(changes) => {
+                assert!(!changes.new.contains(&path), "no-op revert must be removed from `new`");
+            }
+            _ => panic!("expected ServerState::Ok"),
+        }
+
+        match receiver.recv().await.unwrap() {
+            BroadcastMessage::Change(event) => {
+                assert_eq!(event.path, path);
+                assert_eq!(event.kind, ChangeEventKind::Reconciled { cancels: publish_seq });
+            }
+            other => panic!("expected a Reconciled change, got {other:?}"),
+        }
+
+        // a late subscriber backfilling from before the `New` also sees the
+        // retraction following it, matching what `/drain_new` would show
+        let (backlog, _) = event_bus.subscribe(None);
+        assert!(backlog
+            .iter()
+            .any(|e| e.path == path && e.kind == ChangeEventKind::Reconciled { cancels: publish_seq }));
+
+        tokio::fs::remove_file(&path).await.unwrap();
+    }
+}